@@ -0,0 +1,164 @@
+//! The `#[memoize]` attribute macro.
+//!
+//! Applied to a pure `fn foo(args...) -> T`, it renames the body to an inner
+//! function, packs the parameters into a tuple key (relying on each
+//! parameter's own `Track` impl, e.g. from `impl_track_hash!`), and rewrites
+//! `foo` to call `comemo::memoized`. This spares callers from hand-writing
+//! the `Track` plumbing and keeps the key hashing in sync with the function
+//! signature.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::parse::{Parse, ParseStream};
+use syn::punctuated::Punctuated;
+use syn::{parse_macro_input, FnArg, Ident, ItemFn, Pat, Token};
+
+/// Memoize a pure function.
+///
+/// - `#[memoize]` routes the call through `comemo::memoized`; the return
+///   type must implement `Clone`.
+/// - `#[memoize(ref)]` routes it through `comemo::memoized_ref` instead and
+///   wraps the result in an `Arc`, so a cache hit clones a pointer rather
+///   than the (potentially expensive) value itself.
+/// - `#[memoize(ignore = name)]` excludes parameter `name` from the cache
+///   key by wrapping it in `comemo::Ignore`. Repeat for multiple parameters:
+///   `#[memoize(ignore = a, ignore = b)]`.
+#[proc_macro_attribute]
+pub fn memoize(args: TokenStream, input: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(args as MemoizeArgs);
+    let func = parse_macro_input!(input as ItemFn);
+    expand(args, func).into()
+}
+
+/// Parsed arguments to `#[memoize(..)]`.
+#[derive(Default)]
+struct MemoizeArgs {
+    /// Whether to use `memoized_ref` with an `Arc`-wrapped result instead of
+    /// `memoized`.
+    by_ref: bool,
+    /// Parameters excluded from the cache key.
+    ignored: Vec<Ident>,
+}
+
+impl Parse for MemoizeArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut args = MemoizeArgs::default();
+        for item in Punctuated::<MemoizeArg, Token![,]>::parse_terminated(input)? {
+            match item {
+                MemoizeArg::Ref => args.by_ref = true,
+                MemoizeArg::Ignore(ident) => args.ignored.push(ident),
+            }
+        }
+        Ok(args)
+    }
+}
+
+/// A single, comma-separated argument to `#[memoize(..)]`.
+enum MemoizeArg {
+    Ref,
+    Ignore(Ident),
+}
+
+impl Parse for MemoizeArg {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        // `ref` is a keyword, which `Ident`'s parser rejects.
+        if input.peek(Token![ref]) {
+            input.parse::<Token![ref]>()?;
+            return Ok(MemoizeArg::Ref);
+        }
+        let ident: Ident = input.parse()?;
+        if ident == "ignore" {
+            input.parse::<Token![=]>()?;
+            return Ok(MemoizeArg::Ignore(input.parse()?));
+        }
+        Err(syn::Error::new(ident.span(), "expected `ref` or `ignore = <param>`"))
+    }
+}
+
+/// Expand a `#[memoize]`-annotated function into its memoized form.
+fn expand(args: MemoizeArgs, func: ItemFn) -> TokenStream2 {
+    if !func.sig.generics.params.is_empty() {
+        return syn::Error::new_spanned(&func.sig.generics, "generic functions cannot be memoized")
+            .to_compile_error();
+    }
+
+    let attrs = &func.attrs;
+    let vis = &func.vis;
+    let name = &func.sig.ident;
+    let block = &func.block;
+    let output_ty = match &func.sig.output {
+        syn::ReturnType::Type(_, ty) => quote!(#ty),
+        syn::ReturnType::Default => quote!(()),
+    };
+
+    let mut names = Vec::new();
+    let mut tys = Vec::new();
+    let mut ignored = Vec::new();
+    for arg in &func.sig.inputs {
+        let FnArg::Typed(pat_type) = arg else {
+            return syn::Error::new_spanned(arg, "methods cannot be memoized").to_compile_error();
+        };
+        let Pat::Ident(pat_ident) = pat_type.pat.as_ref() else {
+            return syn::Error::new_spanned(
+                &pat_type.pat,
+                "memoized parameters must be simple identifiers",
+            )
+            .to_compile_error();
+        };
+        ignored.push(args.ignored.iter().any(|i| *i == pat_ident.ident));
+        names.push(pat_ident.ident.clone());
+        tys.push(pat_type.ty.clone());
+    }
+
+    let key_tys = tys.iter().zip(&ignored).map(|(ty, ignore)| {
+        if *ignore { quote!(comemo::Ignore<#ty>) } else { quote!(#ty) }
+    });
+    let pack = names.iter().zip(&ignored).map(|(name, ignore)| {
+        if *ignore { quote!(comemo::Ignore(#name)) } else { quote!(#name) }
+    });
+    let unpack = names.iter().zip(&ignored).map(|(name, ignore)| {
+        if *ignore { quote!(let #name = #name.0;) } else { quote!() }
+    });
+    let input_ty = quote!((#(#key_tys,)*));
+
+    let inner_ident = Ident::new(&format!("__{name}_memoize_inner"), name.span());
+    let wrapper_ident = Ident::new(&format!("__{name}_memoize_wrapper"), name.span());
+
+    if args.by_ref {
+        quote! {
+            #(#attrs)*
+            #vis fn #name(#(#names: #tys),*) -> ::std::sync::Arc<#output_ty> {
+                fn #inner_ident(#(#names: #tys),*) -> #output_ty #block
+
+                fn #wrapper_ident(
+                    input: #input_ty,
+                ) -> (::std::sync::Arc<#output_ty>, <#input_ty as comemo::Track>::Constraint) {
+                    let (#(#names,)*) = input;
+                    #(#unpack)*
+                    let output = ::std::sync::Arc::new(#inner_ident(#(#names),*));
+                    (output, ::std::default::Default::default())
+                }
+
+                comemo::memoized_ref((#(#pack,)*), #wrapper_ident, ::std::sync::Arc::clone)
+            }
+        }
+    } else {
+        quote! {
+            #(#attrs)*
+            #vis fn #name(#(#names: #tys),*) -> #output_ty {
+                fn #inner_ident(#(#names: #tys),*) -> #output_ty #block
+
+                fn #wrapper_ident(
+                    input: #input_ty,
+                ) -> (#output_ty, <#input_ty as comemo::Track>::Constraint) {
+                    let (#(#names,)*) = input;
+                    #(#unpack)*
+                    (#inner_ident(#(#names),*), ::std::default::Default::default())
+                }
+
+                comemo::memoized((#(#pack,)*), #wrapper_ident)
+            }
+        }
+    }
+}
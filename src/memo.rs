@@ -1,19 +1,24 @@
 //! Function memoization.
+//!
+//! For the common case of a pure, free function, prefer the `#[memoize]`
+//! attribute macro (in the sibling `macros` crate) over calling `memoized`
+//! by hand.
 
 use std::any::Any;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::fmt::{self, Display, Formatter};
 use std::hash::Hasher;
+use std::sync::{OnceLock, RwLock};
+use std::time::{Duration, Instant};
 
 thread_local! {
     /// The thread-local cache.
     static CACHE: RefCell<Cache> = RefCell::default();
+    /// The thread-local cache's cumulative hit/miss statistics.
+    static STATS: RefCell<Stats> = RefCell::default();
 }
 
-/// A map from hashes to cache entries.
-type Cache = HashMap<u64, CacheEntry>;
-
 /// Access the cache.
 fn with<F, R>(f: F) -> R
 where
@@ -22,12 +27,160 @@ where
     CACHE.with(|cell| f(&mut cell.borrow_mut()))
 }
 
+/// Set the capacity of the thread-local cache, switching it into bounded,
+/// set-associative mode.
+///
+/// The cache is organized into `lines` lines of `width` slots each. A key is
+/// assigned to the line `key % lines`, and on a miss that would overflow a
+/// full line, the slot with the largest `age` in that line is evicted. This
+/// bounds the cache's memory use to `lines * width` entries with only O(width)
+/// work per access.
+///
+/// `age` only advances when `evict()` is called, so the eviction is
+/// LRU-ish only if you still call `evict()` periodically (e.g. once per
+/// compile); without that, every entry's age stays `0` and a full line
+/// evicts its last slot, since `max_by_key` picks the last of equal maxima.
+///
+/// Switching modes discards the current contents of the cache.
+pub fn set_capacity(lines: usize, width: usize) {
+    assert!(lines > 0, "cache must have at least one line");
+    assert!(width > 0, "cache lines must have at least one slot");
+    with(|cache| {
+        *cache = Cache::Bounded { lines: (0..lines).map(|_| Vec::new()).collect(), width }
+    });
+}
+
+/// A cache of memoized results, either unbounded or bounded and
+/// set-associative.
+enum Cache {
+    /// A plain hash map without a capacity limit.
+    Unbounded(HashMap<u64, CacheEntry>),
+    /// A set-associative table with a fixed number of lines, each holding up
+    /// to `width` slots.
+    Bounded { lines: Vec<Vec<(u64, CacheEntry)>>, width: usize },
+}
+
+impl Cache {
+    /// Look up an entry by key.
+    fn get_mut(&mut self, key: u64) -> Option<&mut CacheEntry> {
+        match self {
+            Self::Unbounded(map) => map.get_mut(&key),
+            Self::Bounded { lines, .. } => {
+                let idx = key as usize % lines.len();
+                let line = &mut lines[idx];
+                line.iter_mut().find(|(k, _)| *k == key).map(|(_, entry)| entry)
+            }
+        }
+    }
+
+    /// Insert an entry, evicting another one if necessary.
+    fn insert(&mut self, key: u64, entry: CacheEntry) {
+        match self {
+            Self::Unbounded(map) => {
+                map.insert(key, entry);
+            }
+            Self::Bounded { lines, width } => {
+                let idx = key as usize % lines.len();
+                let line = &mut lines[idx];
+                if let Some(slot) = line.iter_mut().find(|(k, _)| *k == key) {
+                    slot.1 = entry;
+                } else if line.len() < *width {
+                    line.push((key, entry));
+                } else {
+                    // Evict the oldest slot in the line to make room.
+                    let oldest = line
+                        .iter()
+                        .enumerate()
+                        .max_by_key(|(_, (_, entry))| entry.age)
+                        .map(|(i, _)| i)
+                        .unwrap();
+                    line[oldest] = (key, entry);
+                }
+            }
+        }
+    }
+
+    /// The number of entries currently in the cache.
+    fn len(&self) -> usize {
+        match self {
+            Self::Unbounded(map) => map.len(),
+            Self::Bounded { lines, .. } => lines.iter().map(Vec::len).sum(),
+        }
+    }
+
+    /// The estimated number of bytes currently held by the cache's entries.
+    fn bytes(&self) -> usize {
+        match self {
+            Self::Unbounded(map) => map.values().map(|entry| entry.size).sum(),
+            Self::Bounded { lines, .. } => {
+                lines.iter().flatten().map(|(_, entry)| entry.size).sum()
+            }
+        }
+    }
+
+    /// Age all entries and drop those older than `max_age` or past their TTL.
+    fn retain_fresh(&mut self, max_age: usize) {
+        match self {
+            Self::Unbounded(map) => map.retain(|_, entry| {
+                entry.age += 1;
+                entry.age <= max_age && !entry.expired()
+            }),
+            Self::Bounded { lines, .. } => {
+                for line in lines.iter_mut() {
+                    line.retain_mut(|(_, entry)| {
+                        entry.age += 1;
+                        entry.age <= max_age && !entry.expired()
+                    });
+                }
+            }
+        }
+    }
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Self::Unbounded(HashMap::new())
+    }
+}
+
 /// An entry in the cache.
 struct CacheEntry {
     /// The memoized function's result plus constraints on the input.
     data: Box<dyn Any>,
     /// How many evictions have passed since the entry has been last used.
     age: usize,
+    /// `size_of::<(O, I::Constraint)>()` for this entry's erased data, plus
+    /// whatever `HeapSize::heap_size` reported (`0` for entries inserted
+    /// through `memoized`/`memoized_ref`, which don't require that bound).
+    /// Used for `Stats::bytes`.
+    size: usize,
+    /// When set, a `(inserted, ttl)` pair: the entry expires once `inserted`
+    /// is more than `ttl` in the past. Entries inserted through
+    /// `memoized`/`memoized_ref` leave this `None`, so they never pay for a
+    /// clock read on insert.
+    expiry: Option<(Instant, Duration)>,
+}
+
+impl CacheEntry {
+    /// Whether the entry has outlived its time-to-live, if it has one.
+    fn expired(&self) -> bool {
+        self.expiry.is_some_and(|(inserted, ttl)| inserted.elapsed() > ttl)
+    }
+}
+
+/// A hook for types to report how much heap memory they occupy, beyond
+/// their own `size_of`.
+///
+/// Implement this for a memoized function's output or constraint type to
+/// make `Stats::bytes` a more accurate estimate when used with
+/// `memoized_ttl`/`memoized_ref_ttl`. The plain `memoized`/`memoized_ref`
+/// don't require this bound, so existing call sites aren't affected; their
+/// entries are sized with `size_of` alone.
+pub trait HeapSize {
+    /// An estimate of the heap memory (in bytes) owned by this value.
+    fn heap_size(&self) -> usize {
+        0
+    }
 }
 
 /// Execute a memoized function call.
@@ -59,6 +212,66 @@ pub fn memoized_ref<I, O, G, R>(
     f: fn(input: I) -> (O, I::Constraint),
     g: G,
 ) -> R
+where
+    I: Track,
+    O: 'static,
+    G: Fn(&O) -> R,
+{
+    memoized_ref_impl(input, f, g, None, |_, _| 0)
+}
+
+/// Like `memoized`, but entries expire after `ttl` regardless of how often
+/// they're hit.
+///
+/// Useful for memoizing lookups backed by an external, mutable resource
+/// (e.g. reading a file) where staleness, not just call count, matters.
+/// Since such resources (file contents, decoded images, ...) are exactly
+/// the outputs worth sizing accurately, this requires `O` and
+/// `I::Constraint` to implement `HeapSize`.
+pub fn memoized_ttl<I, O>(
+    input: I,
+    f: fn(input: I) -> (O, I::Constraint),
+    ttl: Duration,
+) -> O
+where
+    I: Track,
+    O: Clone + HeapSize + 'static,
+    I::Constraint: HeapSize,
+{
+    memoized_ref_ttl(input, f, Clone::clone, ttl)
+}
+
+/// Like `memoized_ref`, but entries expire after `ttl` regardless of how
+/// often they're hit.
+pub fn memoized_ref_ttl<I, O, G, R>(
+    input: I,
+    f: fn(input: I) -> (O, I::Constraint),
+    g: G,
+    ttl: Duration,
+) -> R
+where
+    I: Track,
+    O: HeapSize + 'static,
+    I::Constraint: HeapSize,
+    G: Fn(&O) -> R,
+{
+    memoized_ref_impl(input, f, g, Some(ttl), |output, constraint| {
+        output.heap_size() + constraint.heap_size()
+    })
+}
+
+/// Shared implementation of `memoized_ref` and `memoized_ref_ttl`.
+///
+/// `heap_size` estimates the heap memory owned by a fresh `(O,
+/// I::Constraint)` pair; `memoized_ref` passes a trivial `0` so it isn't
+/// forced to require `HeapSize` of its callers.
+fn memoized_ref_impl<I, O, G, R>(
+    input: I,
+    f: fn(input: I) -> (O, I::Constraint),
+    g: G,
+    ttl: Option<Duration>,
+    heap_size: fn(&O, &I::Constraint) -> usize,
+) -> R
 where
     I: Track,
     O: 'static,
@@ -68,24 +281,133 @@ where
     input.key(&mut state);
 
     let key = state.finish();
-    let result = with(|cache| {
-        let entry = cache.get_mut(&key)?;
+    let probe = with(|cache| {
+        let entry = cache.get_mut(key)?;
+        if entry.expired() {
+            return None;
+        }
         entry.age = 0;
-        entry
-            .data
-            .downcast_ref::<(O, I::Constraint)>()
-            .filter(|(_, constraint)| input.matches(constraint))
-            .map(|(output, _)| g(output))
+        Some(
+            entry
+                .data
+                .downcast_ref::<(O, I::Constraint)>()
+                .filter(|(_, constraint)| input.matches(constraint))
+                .map(|(output, _)| g(output)),
+        )
     });
 
+    match probe {
+        Some(Some(result)) => {
+            STATS.with(|cell| cell.borrow_mut().hits += 1);
+            result
+        }
+        miss => {
+            STATS.with(|cell| {
+                let mut stats = cell.borrow_mut();
+                if miss.is_some() {
+                    stats.mismatches += 1;
+                } else {
+                    stats.misses += 1;
+                }
+            });
+            let output = f(input);
+            let result = g(&output.0);
+            let size = std::mem::size_of::<(O, I::Constraint)>()
+                + heap_size(&output.0, &output.1);
+            let entry = CacheEntry {
+                data: Box::new(output) as Box<(O, I::Constraint)> as Box<dyn Any>,
+                age: 0,
+                size,
+                expiry: ttl.map(|ttl| (Instant::now(), ttl)),
+            };
+            with(|cache| cache.insert(key, entry));
+            result
+        }
+    }
+}
+
+/// The number of shards the shared cache is split into.
+///
+/// Each shard has its own lock, so keys hashing to different shards never
+/// contend with each other.
+const SHARED_SHARDS: usize = 16;
+
+/// The process-global, sharded cache used by `memoized_shared` and
+/// `memoized_ref_shared`.
+static SHARED_CACHE: OnceLock<Vec<RwLock<HashMap<u64, SharedCacheEntry>>>> = OnceLock::new();
+
+/// Access the shard responsible for `key`.
+fn shared_shard(key: u64) -> &'static RwLock<HashMap<u64, SharedCacheEntry>> {
+    let shards = SHARED_CACHE
+        .get_or_init(|| (0..SHARED_SHARDS).map(|_| RwLock::new(HashMap::new())).collect());
+    &shards[key as usize % shards.len()]
+}
+
+/// An entry in the shared cache.
+struct SharedCacheEntry {
+    /// The memoized function's result plus constraints on the input.
+    data: Box<dyn Any + Send + Sync>,
+}
+
+/// Like `memoized`, but backed by a process-global cache shared across
+/// threads instead of a thread-local one.
+///
+/// Use this for pure functions whose results are worth sharing across a
+/// parallel pipeline (e.g. font loading, parsing, image decoding), so that
+/// work done by one thread isn't redone by another.
+///
+/// Unlike the thread-local cache, entries here are never reclaimed: there
+/// is no `evict()`, capacity limit, or TTL for this cache, so it grows for
+/// as long as the process runs. Only use it for a bounded set of pure
+/// functions (e.g. keyed by something like a font or file identity), not
+/// for anything with an unbounded key space. Hits and misses also don't
+/// show up in `Stats`, which only tracks the thread-local cache.
+pub fn memoized_shared<I, O>(input: I, f: fn(input: I) -> (O, I::Constraint)) -> O
+where
+    I: Track,
+    O: Clone + Send + Sync + 'static,
+    I::Constraint: Send + Sync,
+{
+    memoized_ref_shared(input, f, Clone::clone)
+}
+
+/// Like `memoized_ref`, but backed by a process-global cache shared across
+/// threads instead of a thread-local one.
+pub fn memoized_ref_shared<I, O, G, R>(
+    input: I,
+    f: fn(input: I) -> (O, I::Constraint),
+    g: G,
+) -> R
+where
+    I: Track,
+    O: Send + Sync + 'static,
+    I::Constraint: Send + Sync,
+    G: Fn(&O) -> R,
+{
+    let mut state = fxhash::FxHasher64::default();
+    input.key(&mut state);
+
+    let key = state.finish();
+    let shard = shared_shard(key);
+
+    let result = {
+        let map = shard.read().unwrap();
+        map.get(&key).and_then(|entry| {
+            entry
+                .data
+                .downcast_ref::<(O, I::Constraint)>()
+                .filter(|(_, constraint)| input.matches(constraint))
+                .map(|(output, _)| g(output))
+        })
+    };
+
     result.unwrap_or_else(|| {
         let output = f(input);
         let result = g(&output.0);
-        let entry = CacheEntry {
-            data: Box::new(output) as Box<(O, I::Constraint)> as Box<dyn Any>,
-            age: 0,
+        let entry = SharedCacheEntry {
+            data: Box::new(output) as Box<(O, I::Constraint)> as Box<dyn Any + Send + Sync>,
         };
-        with(|cache| cache.insert(key, entry));
+        shard.write().unwrap().insert(key, entry);
         result
     })
 }
@@ -93,17 +415,15 @@ where
 /// Garbage-collect the thread-local cache.
 ///
 /// This deletes elements which haven't been used in a while and returns details
-/// about the eviction.
+/// about the eviction. In bounded mode, the line capacities already keep
+/// memory use in check, so calling this is optional but still prunes stale
+/// entries that haven't been overwritten yet.
 pub fn evict() -> Eviction {
     with(|cache| {
         const MAX_AGE: usize = 5;
 
         let before = cache.len();
-        cache.retain(|_, entry| {
-            entry.age += 1;
-            entry.age <= MAX_AGE
-        });
-
+        cache.retain_fresh(MAX_AGE);
         Eviction { before, after: cache.len() }
     })
 }
@@ -124,6 +444,49 @@ impl Display for Eviction {
     }
 }
 
+/// Cumulative hit/miss statistics for the thread-local cache.
+#[derive(Default, Clone, Copy)]
+pub struct Stats {
+    /// The number of times a call found a matching, valid cache entry.
+    pub hits: usize,
+    /// The number of times a call found no usable cache entry: either none
+    /// at all, or one past its TTL.
+    pub misses: usize,
+    /// The number of times a call found a cache entry whose constraint no
+    /// longer matched the input, forcing a recompute.
+    pub mismatches: usize,
+    /// An estimate of the memory currently held by the cache: `size_of`
+    /// for every entry, plus `HeapSize::heap_size` for entries inserted via
+    /// `memoized_ttl`/`memoized_ref_ttl`. Entries from `memoized`/
+    /// `memoized_ref` don't require `HeapSize`, so theirs is `size_of`
+    /// alone and can undercount if they own heap allocations.
+    pub bytes: usize,
+}
+
+/// The current hit/miss statistics for the thread-local cache.
+pub fn stats() -> Stats {
+    let mut stats = STATS.with(|cell| *cell.borrow());
+    stats.bytes = with(|cache| cache.bytes());
+    stats
+}
+
+/// Reset the thread-local cache's cumulative hit/miss statistics.
+pub fn reset_stats() {
+    STATS.with(|cell| *cell.borrow_mut() = Stats::default());
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let total = self.hits + self.misses + self.mismatches;
+        let rate = if total > 0 { self.hits as f64 / total as f64 * 100.0 } else { 0.0 };
+        writeln!(f, "Hits: {}", self.hits)?;
+        writeln!(f, "Misses: {}", self.misses)?;
+        writeln!(f, "Mismatches: {}", self.mismatches)?;
+        writeln!(f, "Hit rate: {rate:.1}%")?;
+        writeln!(f, "Memory: ~{} bytes", self.bytes)
+    }
+}
+
 /// Tracks input dependencies of a memoized function.
 pub trait Track {
     /// The type of constraint generated by this input.
@@ -195,8 +558,130 @@ macro_rules! impl_track_tuple {
     };
 }
 
+/// Wraps a value so that it is excluded from a memoized function's cache
+/// key.
+///
+/// This is what the `#[memoize(ignore = ..)]` attribute macro (in the
+/// `macros` crate) wraps side-channel parameters in: their value is neither
+/// hashed nor checked against a constraint, so they can be threaded through a
+/// memoized function (e.g. a rendering context) without busting the cache.
+pub struct Ignore<T>(pub T);
+
+impl<T> Track for Ignore<T> {
+    type Constraint = ();
+
+    fn key<H: Hasher>(&self, _: &mut H) {}
+
+    fn matches(&self, _: &Self::Constraint) -> bool {
+        true
+    }
+}
+
 impl_track_tuple! {}
 impl_track_tuple! { 0: A }
 impl_track_tuple! { 0: A, 1: B }
 impl_track_tuple! { 0: A, 1: B, 2: C }
 impl_track_tuple! { 0: A, 1: B, 2: C, 3: D }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::hash::Hash;
+
+    impl_track_hash!(u64);
+    impl HeapSize for u64 {}
+    impl HeapSize for () {}
+
+    /// A key whose hash only depends on `id`, with `version` carried as the
+    /// constraint. Lets tests force a constraint mismatch without relying on
+    /// an actual hash collision.
+    #[derive(Clone, Copy)]
+    struct Versioned {
+        id: u64,
+        version: u64,
+    }
+
+    impl Track for Versioned {
+        type Constraint = u64;
+
+        fn key<H: Hasher>(&self, hasher: &mut H) {
+            self.id.hash(hasher);
+        }
+
+        fn matches(&self, constraint: &Self::Constraint) -> bool {
+            self.version == *constraint
+        }
+    }
+
+    fn compute(v: Versioned) -> (u64, u64) {
+        (v.version * 2, v.version)
+    }
+
+    #[test]
+    fn hit_then_miss_then_mismatch_are_classified_correctly() {
+        reset_stats();
+
+        let v1 = Versioned { id: 1, version: 1 };
+        assert_eq!(memoized(v1, compute), 2);
+        assert_eq!(stats().misses, 1);
+
+        assert_eq!(memoized(v1, compute), 2);
+        assert_eq!(stats().hits, 1);
+
+        // Same `id`, so same cache key, but a different `version` makes the
+        // constraint check fail.
+        let v2 = Versioned { id: 1, version: 2 };
+        assert_eq!(memoized(v2, compute), 4);
+
+        let s = stats();
+        assert_eq!(s.hits, 1);
+        assert_eq!(s.misses, 1);
+        assert_eq!(s.mismatches, 1);
+    }
+
+    #[test]
+    fn ttl_entry_is_a_miss_once_stale() {
+        reset_stats();
+
+        let ttl = Duration::from_millis(10);
+        assert_eq!(memoized_ttl(1u64, |x| (x * 2, ()), ttl), 2);
+        assert_eq!(stats().misses, 1);
+
+        assert_eq!(memoized_ttl(1u64, |x| (x * 2, ()), ttl), 2);
+        assert_eq!(stats().hits, 1);
+
+        std::thread::sleep(ttl * 2);
+
+        assert_eq!(memoized_ttl(1u64, |x| (x * 2, ()), ttl), 2);
+        let s = stats();
+        assert_eq!(s.hits, 1);
+        assert_eq!(s.misses, 2);
+    }
+
+    #[test]
+    fn eviction_picks_the_oldest_slot_in_a_line() {
+        set_capacity(1, 2);
+
+        // Both land in the cache's single line.
+        assert_eq!(memoized(Versioned { id: 1, version: 0 }, compute), 0);
+        assert_eq!(memoized(Versioned { id: 2, version: 0 }, compute), 0);
+
+        // `evict()` ages every entry in the line by one...
+        evict();
+        // ...then touching `id: 1` resets just its age back to zero, so
+        // `id: 2` is now the uniquely oldest slot.
+        memoized(Versioned { id: 1, version: 0 }, compute);
+
+        // The line is full; inserting a third key evicts the oldest slot,
+        // which is `id: 2`'s.
+        assert_eq!(memoized(Versioned { id: 3, version: 0 }, compute), 0);
+
+        reset_stats();
+        memoized(Versioned { id: 1, version: 0 }, compute);
+        assert_eq!(stats().hits, 1, "id: 1 should have survived eviction");
+
+        reset_stats();
+        memoized(Versioned { id: 2, version: 0 }, compute);
+        assert_eq!(stats().misses, 1, "id: 2 should have been evicted");
+    }
+}